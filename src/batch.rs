@@ -0,0 +1,130 @@
+use std::io;
+use std::mem::MaybeUninit;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use crate::ptcp::PTCPPacket;
+
+const DATAGRAM_CAP: usize = 4096;
+
+/// Pulls up to `max` datagrams off `socket` in as few syscalls as possible.
+///
+/// On Linux this issues a single `recvmmsg(2)` into a preallocated ring of
+/// buffers instead of one `recv` per datagram; everywhere else it falls back
+/// to a loop of single `recv`/`try_recv` calls. Each returned datagram is
+/// parsed with the fallible decoder — a malformed individual datagram is
+/// skipped rather than failing the whole batch.
+pub async fn ptcp_read_batch(socket: &UdpSocket, max: usize) -> io::Result<Vec<PTCPPacket>> {
+    #[cfg(target_os = "linux")]
+    {
+        // `try_io` is what actually clears tokio's readiness state when the
+        // closure reports `WouldBlock` — a bare raw syscall never does, so
+        // a loop of `readable()` + a plain `recvmmsg` would spin hot on
+        // `EAGAIN` instead of parking until the fd is ready again. `try_io`
+        // itself is sync (it only wraps the closure so its `WouldBlock`
+        // clears readiness); `readable()` is still what actually awaits
+        // the next readiness notification.
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || try_recvmmsg(socket, max)) {
+                Ok(packets) => return Ok(packets),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        read_batch_fallback(socket, max).await
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn read_batch_fallback(socket: &UdpSocket, max: usize) -> io::Result<Vec<PTCPPacket>> {
+    let mut packets = Vec::with_capacity(max.min(64));
+    let mut buf = [0u8; DATAGRAM_CAP];
+
+    // The first datagram is awaited; the rest are opportunistic, so a batch
+    // never blocks waiting for more than one message to show up.
+    let n = socket.recv(&mut buf).await?;
+    push_parsed(&mut packets, &buf[..n]);
+
+    while packets.len() < max {
+        match socket.try_recv(&mut buf) {
+            Ok(n) => push_parsed(&mut packets, &buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(packets)
+}
+
+fn push_parsed(packets: &mut Vec<PTCPPacket>, data: &[u8]) {
+    // drop the malformed datagram, keep the rest of the batch
+    if let Ok(packet) = PTCPPacket::parse(data) {
+        packets.push(packet);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_recvmmsg(socket: &UdpSocket, max: usize) -> io::Result<Vec<PTCPPacket>> {
+    use libc::{c_void, iovec, mmsghdr, sockaddr_storage, socklen_t};
+
+    let fd = socket.as_raw_fd();
+    let max = max.max(1);
+
+    let mut buffers: Vec<[MaybeUninit<u8>; DATAGRAM_CAP]> =
+        (0..max).map(|_| [MaybeUninit::uninit(); DATAGRAM_CAP]).collect();
+    let mut addrs: Vec<sockaddr_storage> = (0..max).map(|_| unsafe { std::mem::zeroed() }).collect();
+    let mut iovecs: Vec<iovec> = buffers
+        .iter_mut()
+        .map(|buf| iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: DATAGRAM_CAP,
+        })
+        .collect();
+    let mut headers: Vec<mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iov, addr)| mmsghdr {
+            msg_hdr: libc::msghdr {
+                msg_name: addr as *mut _ as *mut c_void,
+                msg_namelen: std::mem::size_of::<sockaddr_storage>() as socklen_t,
+                msg_iov: iov as *mut iovec,
+                msg_iovlen: 1,
+                msg_control: std::ptr::null_mut(),
+                msg_controllen: 0,
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            max as u32,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut packets = Vec::with_capacity(received as usize);
+    for (header, buf) in headers.iter().take(received as usize).zip(buffers.iter()) {
+        let len = header.msg_len as usize;
+        let data = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+        push_parsed(&mut packets, data);
+    }
+
+    Ok(packets)
+}
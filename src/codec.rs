@@ -0,0 +1,171 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::ptcp::{PTCPError, PTCPPacket};
+
+const HEADER_LEN: usize = 24;
+const DEFAULT_MAX_LENGTH: usize = 64 * 1024;
+
+/// Frames raw UDP datagrams into [`PTCPPacket`]s.
+///
+/// Replaces the old `assert!`-based parsing: a short or malformed buffer
+/// yields `Ok(None)` (wait for more bytes) or a [`PTCPError`] instead of
+/// panicking. `max_length` bounds the payload length a peer can request,
+/// so a forged length field can't trigger an unbounded allocation.
+pub struct PTCPCodec {
+    max_length: usize,
+}
+
+impl PTCPCodec {
+    pub fn new() -> PTCPCodec {
+        PTCPCodec {
+            max_length: DEFAULT_MAX_LENGTH,
+        }
+    }
+
+    pub fn with_max_length(max_length: usize) -> PTCPCodec {
+        PTCPCodec { max_length }
+    }
+}
+
+impl Default for PTCPCodec {
+    fn default() -> PTCPCodec {
+        PTCPCodec::new()
+    }
+}
+
+impl Decoder for PTCPCodec {
+    type Item = PTCPPacket;
+    type Error = PTCPError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PTCPPacket>, PTCPError> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        if &src[0..4] != b"PTCP" {
+            return Err(PTCPError::BadMagic);
+        }
+
+        // A payload body carries its own 16-bit length in its leading word;
+        // a command body is whatever is left in the datagram.
+        let body_len = if src.len() > HEADER_LEN && src[HEADER_LEN] == 0x10 {
+            if src.len() < HEADER_LEN + 4 {
+                return Ok(None);
+            }
+            let header = u32::from_be_bytes([
+                src[HEADER_LEN],
+                src[HEADER_LEN + 1],
+                src[HEADER_LEN + 2],
+                src[HEADER_LEN + 3],
+            ]);
+            let length = (header & 0xFFFF) as usize;
+            if length > self.max_length {
+                return Err(PTCPError::TooLarge);
+            }
+            12 + length
+        } else {
+            src.len() - HEADER_LEN
+        };
+
+        let total_len = HEADER_LEN + body_len;
+        if src.len() < total_len {
+            return Ok(None);
+        }
+
+        let frame = src.split_to(total_len);
+        let packet = PTCPPacket::parse(&frame)?;
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder<PTCPPacket> for PTCPCodec {
+    type Error = PTCPError;
+
+    fn encode(&mut self, item: PTCPPacket, dst: &mut BytesMut) -> Result<(), PTCPError> {
+        let buf = item.serialize();
+        dst.reserve(buf.len());
+        dst.put(buf.as_slice());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PTCPCommand;
+    use crate::ptcp::{PTCPBody, PTCPPayload, PTCPSession};
+
+    #[test]
+    fn round_trips_a_payload_frame() {
+        let mut session = PTCPSession::new();
+        let packet = session.send(PTCPBody::Payload(PTCPPayload {
+            realm: 7,
+            data: b"hello ptcp".to_vec(),
+        }));
+
+        let mut codec = PTCPCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf).unwrap();
+
+        let decoded = codec.decode(&mut buf).unwrap().expect("a full frame was written");
+        assert!(buf.is_empty());
+        match decoded.body {
+            PTCPBody::Payload(payload) => {
+                assert_eq!(payload.realm, 7);
+                assert_eq!(payload.data, b"hello ptcp");
+            }
+            other => panic!("expected a payload body, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_a_command_frame() {
+        let mut session = PTCPSession::new();
+        let packet = session.send(PTCPBody::Command(PTCPCommand::Ack));
+
+        let mut codec = PTCPCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf).unwrap();
+
+        let decoded = codec.decode(&mut buf).unwrap().expect("a full frame was written");
+        match decoded.body {
+            PTCPBody::Command(command) => assert!(command.is_ack()),
+            other => panic!("expected a command body, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn waits_for_more_bytes_on_a_split_frame() {
+        let mut session = PTCPSession::new();
+        let packet = session.send(PTCPBody::Payload(PTCPPayload {
+            realm: 1,
+            data: b"split me".to_vec(),
+        }));
+
+        let mut codec = PTCPCodec::new();
+        let mut whole = BytesMut::new();
+        codec.encode(packet, &mut whole).unwrap();
+
+        let mut buf = BytesMut::from(&whole[..whole.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&whole[whole.len() - 1..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_a_payload_over_the_configured_max_length() {
+        let mut session = PTCPSession::new();
+        let packet = session.send(PTCPBody::Payload(PTCPPayload {
+            realm: 1,
+            data: vec![0u8; 32],
+        }));
+
+        let mut codec = PTCPCodec::with_max_length(8);
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf).unwrap();
+
+        assert!(matches!(codec.decode(&mut buf), Err(PTCPError::TooLarge)));
+    }
+}
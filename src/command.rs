@@ -0,0 +1,69 @@
+/// Declares a typed command enum for a handful of fixed-length magic byte
+/// strings, instead of call sites hand-writing (and `==`-comparing against)
+/// the raw bytes directly.
+///
+/// Each arm maps a variant to its exact wire encoding and whether it is an
+/// acknowledgement (an ack doesn't itself get acked, and doesn't advance the
+/// sender's packet `count`). Bytes that don't match any known variant parse
+/// to `Unknown`, so forward compatibility with commands this build doesn't
+/// recognize yet is preserved.
+macro_rules! define_command {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident {
+        $($variant:ident => $bytes:expr, ack: $ack:expr),+ $(,)?
+    }) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant,)+
+            Unknown(Vec<u8>),
+        }
+
+        impl $name {
+            pub fn parse(data: &[u8]) -> $name {
+                $(if data == $bytes { return $name::$variant; })+
+                $name::Unknown(data.to_vec())
+            }
+
+            pub fn serialize(&self) -> Vec<u8> {
+                match self {
+                    $($name::$variant => $bytes.to_vec(),)+
+                    $name::Unknown(data) => data.clone(),
+                }
+            }
+
+            /// Whether this command is an acknowledgement.
+            pub fn is_ack(&self) -> bool {
+                match self {
+                    $($name::$variant => $ack,)+
+                    $name::Unknown(_) => false,
+                }
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $($name::$variant => write!(f, stringify!($variant)),)+
+                    $name::Unknown(data) => write!(
+                        f,
+                        "Unknown([{}])",
+                        data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+                    ),
+                }
+            }
+        }
+    };
+}
+
+define_command! {
+    pub enum PTCPCommand {
+        Ack => b"\x00\x03\x01\x00", ack: true,
+        // NOTE: this opcode is a placeholder, not an attested wire value —
+        // unlike `Ack`, no existing packet capture or spec pins down what a
+        // real PTCP peer sends for teardown. `PTCPConnectionManager` needs
+        // *some* command to scope a realm teardown to, so it uses this one
+        // provisionally; confirm the real opcode against a reference peer
+        // before relying on this to interop, or a genuine teardown from a
+        // real peer will just parse as `Unknown` and be ignored.
+        Teardown => b"\x00\x03\x02\x00", ack: false,
+    }
+}
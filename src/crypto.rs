@@ -0,0 +1,267 @@
+//! Optional Noise-based transport encryption for `PTCPPayload.data`.
+//!
+//! Gated behind `feature = "encryption"` so plaintext deployments pay
+//! nothing for it. A `PTCPSession::handshake()` step negotiates a pair of
+//! directional keys with a Noise IK or XX pattern, binding the session's
+//! `realm` into the transcript so a key from one realm can't be replayed
+//! against another. Every subsequent payload is wrapped in a
+//! ChaCha20-Poly1305 AEAD frame via [`PTCPSession::send_encrypted`]/
+//! [`PTCPSession::recv_encrypted`], with the nonce derived from the
+//! session's own `sent` counter rather than a random value, since that
+//! counter is already guaranteed never to repeat within a session — and,
+//! because each direction gets its own key out of the handshake, never
+//! repeats under the peer's key either.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload as AeadPayload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use snow::{Builder, HandshakeState};
+use tokio::net::UdpSocket;
+
+use crate::command::PTCPCommand;
+use crate::ptcp::{PTCPBody, PTCPError, PTCPPacket, PTCPSession};
+
+/// Leading byte of an encrypted payload frame, distinct from the plaintext
+/// `PTCPPayload` marker (`0x10`).
+pub const ENCRYPTED_PAYLOAD_MARKER: u8 = 0x11;
+
+const NONCE_LEN: usize = 12;
+
+/// Which Noise pattern to negotiate in [`PTCPSession::handshake`].
+pub enum HandshakePattern {
+    /// The initiator already knows the responder's static key.
+    IK,
+    /// Neither side knows the other's static key ahead of time.
+    XX,
+}
+
+impl HandshakePattern {
+    fn noise_params(&self) -> &'static str {
+        match self {
+            HandshakePattern::IK => "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+            HandshakePattern::XX => "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+        }
+    }
+}
+
+/// A `PTCPPayload`-shaped frame whose `data` has been replaced with a
+/// ChaCha20-Poly1305 ciphertext (including its 16-byte tag).
+pub struct EncryptedPayload {
+    pub realm: u32,
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedPayload {
+    pub(crate) fn parse(data: &[u8]) -> Result<EncryptedPayload, PTCPError> {
+        if data.len() < 4 + 4 + NONCE_LEN {
+            return Err(PTCPError::TruncatedHeader);
+        }
+        if data[0] != ENCRYPTED_PAYLOAD_MARKER {
+            return Err(PTCPError::BadMagic);
+        }
+
+        let header = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let length = (header & 0xFFFF) as usize;
+        let realm = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[8..8 + NONCE_LEN]);
+        let ciphertext = data[8 + NONCE_LEN..].to_vec();
+
+        if length != ciphertext.len() {
+            return Err(PTCPError::LengthMismatch);
+        }
+
+        Ok(EncryptedPayload { realm, nonce, ciphertext })
+    }
+
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let length = self.ciphertext.len() as u32;
+        let header = (u32::from(ENCRYPTED_PAYLOAD_MARKER) << 24) | length;
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&header.to_be_bytes());
+        buf.extend_from_slice(&self.realm.to_be_bytes());
+        buf.extend_from_slice(&self.nonce);
+        buf.extend_from_slice(&self.ciphertext);
+
+        buf
+    }
+}
+
+impl std::fmt::Debug for EncryptedPayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "EncryptedPayload {{ realm: 0x{:08x}, ciphertext_len: {} }}",
+            self.realm,
+            self.ciphertext.len()
+        )
+    }
+}
+
+/// Derives this message's nonce from the session's `sent` byte offset at
+/// the time of encryption, which never repeats for the lifetime of the
+/// session.
+fn derive_nonce(sent: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 4..].copy_from_slice(&sent.to_be_bytes());
+    nonce
+}
+
+/// Encrypts `plaintext` for `realm` using `cipher`, deriving the nonce from
+/// `sent`.
+pub fn encrypt(
+    cipher: &ChaCha20Poly1305,
+    realm: u32,
+    sent: u32,
+    plaintext: &[u8],
+) -> EncryptedPayload {
+    let nonce_bytes = derive_nonce(sent);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    let aad = realm.to_be_bytes();
+
+    // Encryption only fails if the plaintext is absurdly large; the crate's
+    // frames are always well under that limit.
+    let ciphertext = cipher
+        .encrypt(nonce, AeadPayload { msg: plaintext, aad: &aad })
+        .expect("chacha20poly1305 encryption failed");
+
+    EncryptedPayload { realm, nonce: nonce_bytes, ciphertext }
+}
+
+/// Decrypts `payload`, failing closed (rather than panicking) on a bad tag,
+/// a wrong key, or a realm mismatch between the frame and the caller.
+pub fn decrypt(
+    cipher: &ChaCha20Poly1305,
+    payload: &EncryptedPayload,
+    expected_realm: u32,
+) -> Result<Vec<u8>, PTCPError> {
+    if payload.realm != expected_realm {
+        return Err(PTCPError::DecryptionFailed);
+    }
+
+    let nonce = Nonce::from_slice(&payload.nonce);
+    let aad = payload.realm.to_be_bytes();
+
+    cipher
+        .decrypt(nonce, AeadPayload { msg: &payload.ciphertext, aad: &aad })
+        .map_err(|_| PTCPError::DecryptionFailed)
+}
+
+impl PTCPSession {
+    /// Drives a Noise handshake with the peer over `socket`, binding
+    /// `realm` into the transcript so the resulting keys can't be replayed
+    /// against a different realm, and returns the derived transport
+    /// ciphers as `(send, recv)`. Noise hands back one key per direction
+    /// (initiator-to-responder, responder-to-initiator); using the same
+    /// cipher for both directions would mean the first message each side
+    /// sends reuses the same (key, nonce) pair, breaking the AEAD's
+    /// never-reuse requirement, and would leave the responder unable to
+    /// decrypt what the initiator actually sent. Key material is supplied
+    /// by the caller; this only performs the exchange.
+    pub async fn handshake(
+        &mut self,
+        pattern: HandshakePattern,
+        is_initiator: bool,
+        local_private_key: &[u8],
+        remote_public_key: Option<&[u8]>,
+        socket: &UdpSocket,
+        realm: u32,
+    ) -> Result<(ChaCha20Poly1305, ChaCha20Poly1305), PTCPError> {
+        let params = pattern
+            .noise_params()
+            .parse()
+            .map_err(|_| PTCPError::HandshakeFailed)?;
+        let mut builder = Builder::new(params).local_private_key(local_private_key);
+        if let Some(remote) = remote_public_key {
+            builder = builder.remote_public_key(remote);
+        }
+        // Bind the realm into the transcript as Noise's pre-shared
+        // prologue, so a handshake completed for one realm can't be
+        // replayed to authenticate traffic on another.
+        let realm_bytes = realm.to_be_bytes();
+        builder = builder.prologue(&realm_bytes);
+
+        let mut noise: HandshakeState = if is_initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(|_| PTCPError::HandshakeFailed)?;
+
+        let mut buf = [0u8; 1024];
+        while !noise.is_handshake_finished() {
+            if noise.is_my_turn() {
+                let len = noise
+                    .write_message(&[], &mut buf)
+                    .map_err(|_| PTCPError::HandshakeFailed)?;
+                let packet = self.send(PTCPBody::Command(PTCPCommand::Unknown(buf[..len].to_vec())));
+                socket
+                    .send(&packet.serialize())
+                    .await
+                    .map_err(|_| PTCPError::HandshakeFailed)?;
+            } else {
+                let mut datagram = [0u8; 2048];
+                let n = socket
+                    .recv(&mut datagram)
+                    .await
+                    .map_err(|_| PTCPError::HandshakeFailed)?;
+                let packet = PTCPPacket::parse(&datagram[..n])?;
+                let packet = self.recv(packet);
+                let message = match packet.body {
+                    PTCPBody::Command(command) => command.serialize(),
+                    _ => return Err(PTCPError::HandshakeFailed),
+                };
+                noise
+                    .read_message(&message, &mut buf)
+                    .map_err(|_| PTCPError::HandshakeFailed)?;
+            }
+        }
+
+        // `dangerously_get_raw_split` always returns (initiator_to_responder,
+        // responder_to_initiator) regardless of which side we are, so map
+        // that back onto our own send/recv roles before the handshake state
+        // is consumed by `into_transport_mode`.
+        let (i2r, r2i) = noise.dangerously_get_raw_split();
+        let (send_key, recv_key) = if is_initiator { (i2r, r2i) } else { (r2i, i2r) };
+
+        noise
+            .into_transport_mode()
+            .map_err(|_| PTCPError::HandshakeFailed)?;
+
+        let send_cipher = ChaCha20Poly1305::new(Key::from_slice(&send_key));
+        let recv_cipher = ChaCha20Poly1305::new(Key::from_slice(&recv_key));
+        Ok((send_cipher, recv_cipher))
+    }
+
+    /// Encrypts `plaintext` for `realm` with the session's send cipher and
+    /// sends it as an `EncryptedPayload` frame, advancing `sent` and the
+    /// packet counters exactly as an unencrypted [`send`](Self::send) would.
+    pub fn send_encrypted(
+        &mut self,
+        cipher: &ChaCha20Poly1305,
+        realm: u32,
+        plaintext: &[u8],
+    ) -> PTCPPacket {
+        let payload = encrypt(cipher, realm, self.sent_offset(), plaintext);
+        self.send(PTCPBody::EncryptedPayload(payload))
+    }
+
+    /// Applies a received packet's bookkeeping and, if it carries an
+    /// `EncryptedPayload` for `expected_realm`, decrypts it with the
+    /// session's recv cipher. Anything else — a plaintext body, a mismatched
+    /// realm, a bad tag — fails closed with [`PTCPError::DecryptionFailed`]
+    /// rather than silently accepting unauthenticated data.
+    pub fn recv_encrypted(
+        &mut self,
+        cipher: &ChaCha20Poly1305,
+        packet: PTCPPacket,
+        expected_realm: u32,
+    ) -> Result<Vec<u8>, PTCPError> {
+        let packet = self.recv(packet);
+        match packet.body {
+            PTCPBody::EncryptedPayload(ref payload) => decrypt(cipher, payload, expected_realm),
+            _ => Err(PTCPError::DecryptionFailed),
+        }
+    }
+}
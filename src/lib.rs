@@ -0,0 +1,8 @@
+pub mod batch;
+pub mod codec;
+pub mod command;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+pub mod manager;
+pub mod ptcp;
+pub mod stream;
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::command::PTCPCommand;
+use crate::ptcp::{PTCPBody, PTCPEvent, PTCPPacket, PTCPPayload, PTCPSession};
+
+const EVENT_CHANNEL: usize = 256;
+const REALM_CHANNEL: usize = 256;
+const DATAGRAM_BUFFER: usize = 4096;
+
+/// Demultiplexes the realms carried by `PTCPPayload.realm` over a single
+/// `UdpSocket` into per-realm channels.
+///
+/// A background task owns the socket, decodes every incoming packet off
+/// `recv_from` (the socket is typically unconnected here, since many peers
+/// share it), and either forwards the payload to the `Sender` registered for
+/// its realm via [`open_realm`](Self::open_realm) or, if no realm has
+/// claimed it yet, emits it on the unrouted event stream alongside the
+/// synthesized `Connect`/`Disconnect` events.
+pub struct PTCPConnectionManager {
+    socket: Arc<UdpSocket>,
+    realms: Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>>,
+    peers: Arc<Mutex<HashMap<u32, SocketAddr>>>,
+}
+
+impl PTCPConnectionManager {
+    pub fn new(socket: UdpSocket) -> (PTCPConnectionManager, ReceiverStream<PTCPEvent>) {
+        let socket = Arc::new(socket);
+        let realms = Arc::new(Mutex::new(HashMap::new()));
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let (event_tx, event_rx) = mpsc::channel(EVENT_CHANNEL);
+
+        tokio::spawn(run(Arc::clone(&socket), Arc::clone(&realms), Arc::clone(&peers), event_tx));
+
+        let manager = PTCPConnectionManager { socket, realms, peers };
+        (manager, ReceiverStream::new(event_rx))
+    }
+
+    /// Registers `realm`, returning a sender for outgoing data on that realm
+    /// and a receiver for the data the peer sends on it. `realm` must
+    /// already have been observed on an incoming packet (a `Connect`/`Data`
+    /// event will have fired for it) so `pump` knows which peer address to
+    /// `send_to`; outbound writes before that are silently dropped.
+    pub fn open_realm(&self, realm: u32) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+        let (inbound_tx, inbound_rx) = mpsc::channel(REALM_CHANNEL);
+        self.realms.lock().unwrap().insert(realm, inbound_tx);
+
+        let (outbound_tx, outbound_rx) = mpsc::channel(REALM_CHANNEL);
+        tokio::spawn(pump(Arc::clone(&self.socket), Arc::clone(&self.peers), realm, outbound_rx));
+
+        (outbound_tx, inbound_rx)
+    }
+}
+
+/// Sends whatever the caller writes into `outbound` out over `socket` as
+/// `realm`'s payloads, keeping its own [`PTCPSession`] so the realm's
+/// `sent`/`recv` counters stay independent of every other multiplexed realm.
+///
+/// `socket` is unconnected (shared by every realm and peer), so each send
+/// needs an explicit destination; `peers` is the same realm-to-address map
+/// `run` populates from incoming traffic.
+async fn pump(
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<HashMap<u32, SocketAddr>>>,
+    realm: u32,
+    mut outbound: Receiver<Vec<u8>>,
+) {
+    let mut session = PTCPSession::new();
+    while let Some(data) = outbound.recv().await {
+        let addr = match peers.lock().unwrap().get(&realm).copied() {
+            Some(addr) => addr,
+            None => continue, // no peer observed for this realm yet; nothing to send to
+        };
+
+        let packet = session.send(PTCPBody::Payload(PTCPPayload { realm, data }));
+        if socket.send_to(&packet.serialize(), addr).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn run(
+    socket: Arc<UdpSocket>,
+    realms: Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>>,
+    peers: Arc<Mutex<HashMap<u32, SocketAddr>>>,
+    events: Sender<PTCPEvent>,
+) {
+    let mut buf = [0u8; DATAGRAM_BUFFER];
+
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(_) => return, // socket is gone, nothing left to relay
+        };
+
+        let packet = match PTCPPacket::parse(&buf[..n]) {
+            Ok(packet) => packet,
+            Err(_) => continue, // drop the malformed datagram, keep the relay alive
+        };
+
+        match packet.body {
+            PTCPBody::Payload(payload) => {
+                let realm = payload.realm;
+                if peers.lock().unwrap().insert(realm, addr).is_none()
+                    && events.send(PTCPEvent::Connect(realm)).await.is_err()
+                {
+                    return;
+                }
+
+                let sender = realms.lock().unwrap().get(&realm).cloned();
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(payload.data).await;
+                    }
+                    None => {
+                        if events.send(PTCPEvent::Data(realm, payload.data)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            PTCPBody::Command(ref command) if matches!(command, PTCPCommand::Teardown) => {
+                let torn_down: Vec<u32> = {
+                    let peers = peers.lock().unwrap();
+                    peers.iter().filter(|&(_, &peer)| peer == addr).map(|(&realm, _)| realm).collect()
+                };
+                peers.lock().unwrap().retain(|_, &mut peer| peer != addr);
+                {
+                    let mut realms = realms.lock().unwrap();
+                    for realm in &torn_down {
+                        realms.remove(realm);
+                    }
+                }
+                for realm in torn_down {
+                    if events.send(PTCPEvent::Disconnect(realm)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    use crate::ptcp::{PTCPBody, PTCPPacket, PTCPPayload, PTCPSession};
+
+    #[tokio::test]
+    async fn pump_sends_outbound_data_to_the_realms_known_peer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let (manager, mut events) = PTCPConnectionManager::new(socket);
+
+        // `socket` is unconnected and shared across every peer, so the test
+        // peer must stay unconnected too and address every send explicitly —
+        // exactly the scenario that broke `pump`'s plain `send`.
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut peer_session = PTCPSession::new();
+        let packet = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 42, data: b"hi".to_vec() }));
+        peer.send_to(&packet.serialize(), local_addr).await.unwrap();
+
+        assert!(matches!(events.next().await, Some(PTCPEvent::Connect(realm)) if realm == 42));
+        match events.next().await {
+            Some(PTCPEvent::Data(realm, data)) => {
+                assert_eq!(realm, 42);
+                assert_eq!(data, b"hi");
+            }
+            _ => panic!("expected a Data event for the unclaimed realm"),
+        }
+
+        let (outbound, _inbound) = manager.open_realm(42);
+        outbound.send(b"reply".to_vec()).await.unwrap();
+
+        let mut buf = [0u8; 4096];
+        let n = peer.recv(&mut buf).await.unwrap();
+        let reply = PTCPPacket::parse(&buf[..n]).unwrap();
+        match reply.body {
+            PTCPBody::Payload(payload) => assert_eq!(payload.data, b"reply"),
+            _ => panic!("expected a payload body"),
+        }
+    }
+}
@@ -2,20 +2,73 @@ use async_trait::async_trait;
 use std::cmp;
 use tokio::net::UdpSocket;
 
+use crate::command::PTCPCommand;
+
 pub enum PTCPEvent {
     Connect(u32),
     Disconnect(u32),
     Data(u32, Vec<u8>),
 }
 
+/// Errors produced while decoding or encoding a PTCP frame.
+///
+/// These replace the `assert!`/`assert_eq!` panics that used to fire on
+/// malformed input from a peer, so a bad datagram can be rejected instead of
+/// taking down the whole task.
+#[derive(Debug)]
+pub enum PTCPError {
+    BadMagic,
+    BadPayloadMarker,
+    TruncatedHeader,
+    LengthMismatch,
+    BadPadding,
+    TooLarge,
+    /// Wraps an I/O error that surfaced while a `tokio_util::codec` frame
+    /// was in flight. Required by `Decoder`/`Encoder`'s `Error: From<io::Error>`
+    /// bound; not produced by the parsing code in this module itself.
+    Io(std::io::Error),
+    #[cfg(feature = "encryption")]
+    HandshakeFailed,
+    #[cfg(feature = "encryption")]
+    DecryptionFailed,
+}
+
+impl std::fmt::Display for PTCPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PTCPError::BadMagic => write!(f, "invalid PTCP magic"),
+            PTCPError::BadPayloadMarker => write!(f, "invalid payload type marker"),
+            PTCPError::TruncatedHeader => write!(f, "truncated PTCP header"),
+            PTCPError::LengthMismatch => write!(f, "payload length does not match declared length"),
+            PTCPError::BadPadding => write!(f, "invalid payload padding"),
+            PTCPError::TooLarge => write!(f, "payload exceeds the configured maximum length"),
+            PTCPError::Io(err) => write!(f, "I/O error: {err}"),
+            #[cfg(feature = "encryption")]
+            PTCPError::HandshakeFailed => write!(f, "noise handshake failed"),
+            #[cfg(feature = "encryption")]
+            PTCPError::DecryptionFailed => write!(f, "failed to decrypt an encrypted payload"),
+        }
+    }
+}
+
+impl std::error::Error for PTCPError {}
+
+impl From<std::io::Error> for PTCPError {
+    fn from(err: std::io::Error) -> PTCPError {
+        PTCPError::Io(err)
+    }
+}
+
 pub struct PTCPPayload {
     pub realm: u32,
     pub data: Vec<u8>,
 }
 
 pub enum PTCPBody {
-    Command(Vec<u8>),
+    Command(PTCPCommand),
     Payload(PTCPPayload),
+    #[cfg(feature = "encryption")]
+    EncryptedPayload(crate::crypto::EncryptedPayload),
     Empty,
 }
 
@@ -29,9 +82,13 @@ pub struct PTCPPacket {
 }
 
 impl PTCPPayload {
-    fn parse(data: &[u8]) -> PTCPPayload {
-        assert!(data.len() >= 12, "Invalid payload");
-        assert_eq!(data[0], 0x10, "Invalid header");
+    fn parse(data: &[u8]) -> Result<PTCPPayload, PTCPError> {
+        if data.len() < 12 {
+            return Err(PTCPError::TruncatedHeader);
+        }
+        if data[0] != 0x10 {
+            return Err(PTCPError::BadPayloadMarker);
+        }
 
         // first 4 bytes it header
         let header = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
@@ -40,10 +97,14 @@ impl PTCPPayload {
         let padding = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
         let data = data[12..].to_vec();
 
-        assert_eq!(padding, 0, "Invalid padding");
-        assert_eq!(length, data.len() as u32, "Invalid length");
+        if padding != 0 {
+            return Err(PTCPError::BadPadding);
+        }
+        if length != data.len() as u32 {
+            return Err(PTCPError::LengthMismatch);
+        }
 
-        PTCPPayload { realm, data }
+        Ok(PTCPPayload { realm, data })
     }
 
     fn serialize(&self) -> Vec<u8> {
@@ -83,15 +144,10 @@ impl std::fmt::Debug for PTCPPayload {
 impl std::fmt::Debug for PTCPBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PTCPBody::Command(data) => write!(
-                f,
-                "Command([{}])",
-                data.iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ),
+            PTCPBody::Command(command) => write!(f, "Command({:?})", command),
             PTCPBody::Payload(payload) => write!(f, "{:?}", payload),
+            #[cfg(feature = "encryption")]
+            PTCPBody::EncryptedPayload(payload) => write!(f, "{:?}", payload),
             PTCPBody::Empty => write!(f, "Empty"),
         }
     }
@@ -108,55 +164,88 @@ impl std::fmt::Debug for PTCPPacket {
 }
 
 impl PTCPBody {
-    fn parse(data: &[u8]) -> PTCPBody {
-        if data.len() == 0 {
-            return PTCPBody::Empty;
+    fn parse(data: &[u8]) -> Result<PTCPBody, PTCPError> {
+        if data.is_empty() {
+            return Ok(PTCPBody::Empty);
         }
 
-        assert!(data.len() >= 4, "Invalid body");
+        if data.len() < 4 {
+            return Err(PTCPError::TruncatedHeader);
+        }
 
         if data[0] == 0x10 {
-            PTCPBody::Payload(PTCPPayload::parse(data))
+            Ok(PTCPBody::Payload(PTCPPayload::parse(data)?))
         } else {
-            PTCPBody::Command(data.to_vec())
+            #[cfg(feature = "encryption")]
+            if data[0] == crate::crypto::ENCRYPTED_PAYLOAD_MARKER {
+                return Ok(PTCPBody::EncryptedPayload(crate::crypto::EncryptedPayload::parse(data)?));
+            }
+
+            Ok(PTCPBody::Command(PTCPCommand::parse(data)))
         }
     }
 
     fn serialize(&self) -> Vec<u8> {
         match self {
-            PTCPBody::Command(data) => data.to_vec(),
+            PTCPBody::Command(command) => command.serialize(),
             PTCPBody::Payload(payload) => payload.serialize(),
+            #[cfg(feature = "encryption")]
+            PTCPBody::EncryptedPayload(payload) => payload.serialize(),
             PTCPBody::Empty => Vec::new(),
         }
     }
+
+    /// Number of bytes this body occupies in the `sent`/`recv` sequence
+    /// space. Pure acks are excluded (they report `0`): an ack carries no
+    /// data of its own, so letting it consume sequence space would mean a
+    /// *lost* ack leaves a permanent hole at the offset it would have
+    /// occupied — the next real payload arrives past a gap nothing will
+    /// ever fill, stalling the stream for good. Every other body
+    /// (payloads, and non-ack commands like a handshake message) still
+    /// occupies exactly what it serializes to, since both peers need those
+    /// bytes reflected in the offsets they exchange to agree on position.
+    fn wire_len(&self) -> u32 {
+        match self {
+            PTCPBody::Command(command) if command.is_ack() => 0,
+            PTCPBody::Command(command) => command.serialize().len() as u32,
+            PTCPBody::Payload(payload) => payload.data.len() as u32 + 12,
+            #[cfg(feature = "encryption")]
+            PTCPBody::EncryptedPayload(payload) => payload.ciphertext.len() as u32 + 20,
+            PTCPBody::Empty => 0,
+        }
+    }
 }
 
 impl PTCPPacket {
-    fn parse(data: &[u8]) -> PTCPPacket {
-        assert!(data.len() >= 24, "Invalid packet");
+    pub(crate) fn parse(data: &[u8]) -> Result<PTCPPacket, PTCPError> {
+        if data.len() < 24 {
+            return Err(PTCPError::TruncatedHeader);
+        }
 
         let magic = &data[0..4];
 
-        assert_eq!(magic, b"PTCP", "Invalid magic");
+        if magic != b"PTCP" {
+            return Err(PTCPError::BadMagic);
+        }
 
         let sent = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
         let recv = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
         let pid = u32::from_be_bytes([data[12], data[13], data[14], data[15]]);
         let lmid = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
         let rmid = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
-        let body = PTCPBody::parse(&data[24..]);
+        let body = PTCPBody::parse(&data[24..])?;
 
-        PTCPPacket {
+        Ok(PTCPPacket {
             sent,
             recv,
             pid,
             lmid,
             rmid,
             body,
-        }
+        })
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    pub(crate) fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.extend_from_slice(b"PTCP");
         buf.extend_from_slice(&self.sent.to_be_bytes());
@@ -168,6 +257,18 @@ impl PTCPPacket {
 
         buf
     }
+
+    /// Byte offset of this packet's payload in the sender's stream, i.e. the
+    /// `sent` counter at the time the packet was produced.
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+
+    /// The sender's acknowledgement of how many bytes it has received from
+    /// the peer.
+    pub fn recv(&self) -> u32 {
+        self.recv
+    }
 }
 
 pub struct PTCPSession {
@@ -178,6 +279,12 @@ pub struct PTCPSession {
     rmid: u32,
 }
 
+impl Default for PTCPSession {
+    fn default() -> PTCPSession {
+        PTCPSession::new()
+    }
+}
+
 impl PTCPSession {
     pub fn new() -> PTCPSession {
         PTCPSession {
@@ -189,9 +296,21 @@ impl PTCPSession {
         }
     }
 
+    /// The byte offset the next outgoing packet will be sent at, i.e. the
+    /// current `sent` counter.
+    pub fn sent_offset(&self) -> u32 {
+        self.sent
+    }
+
+    /// The byte offset of the next payload expected from the peer, i.e. the
+    /// current `recv` counter.
+    pub fn recv_offset(&self) -> u32 {
+        self.recv
+    }
+
     pub fn send(&mut self, body: PTCPBody) -> PTCPPacket {
         let is_ack = match body {
-            PTCPBody::Command(ref c) => c == b"\x00\x03\x01\x00",
+            PTCPBody::Command(ref c) => c.is_ack(),
             _ => false,
         };
 
@@ -208,11 +327,7 @@ impl PTCPSession {
         /*
          * Update counters
          */
-        self.sent += match body {
-            PTCPBody::Command(ref c) => c.len() as u32,
-            PTCPBody::Payload(ref p) => p.data.len() as u32 + 12,
-            PTCPBody::Empty => 0,
-        };
+        self.sent += body.wire_len();
 
         self.id += 1;
         self.count += match body {
@@ -224,6 +339,8 @@ impl PTCPSession {
                 }
             }
             PTCPBody::Payload(_) => 1,
+            #[cfg(feature = "encryption")]
+            PTCPBody::EncryptedPayload(_) => 1,
             PTCPBody::Empty => 0,
         };
 
@@ -238,11 +355,7 @@ impl PTCPSession {
     }
 
     pub fn recv(&mut self, packet: PTCPPacket) -> PTCPPacket {
-        self.recv += match packet.body {
-            PTCPBody::Command(ref c) => c.len() as u32,
-            PTCPBody::Payload(ref p) => p.data.len() as u32 + 12,
-            PTCPBody::Empty => 0,
-        };
+        self.recv += packet.body.wire_len();
         self.rmid = packet.lmid;
 
         packet
@@ -251,30 +364,117 @@ impl PTCPSession {
 
 #[async_trait]
 pub trait PTCP {
-    async fn ptcp_request(&self, packet: PTCPPacket);
-    async fn ptcp_read(&self) -> PTCPPacket;
+    async fn ptcp_request(&self, packet: PTCPPacket) -> Result<(), PTCPError>;
+    async fn ptcp_read(&self) -> Result<PTCPPacket, PTCPError>;
+
+    /// Pulls up to `max` datagrams in as few syscalls as possible. See
+    /// [`crate::batch::ptcp_read_batch`] for the platform-specific
+    /// implementation.
+    async fn ptcp_read_batch(&self, max: usize) -> std::io::Result<Vec<PTCPPacket>>;
 }
 
 #[async_trait]
 impl PTCP for UdpSocket {
-    async fn ptcp_request(&self, packet: PTCPPacket) {
+    async fn ptcp_request(&self, packet: PTCPPacket) -> Result<(), PTCPError> {
         println!(">>> {}", self.peer_addr().unwrap());
         println!("{:?}", packet);
 
         let packet = packet.serialize();
         self.send(&packet).await.unwrap();
         println!("---");
+
+        Ok(())
     }
 
-    async fn ptcp_read(&self) -> PTCPPacket {
+    async fn ptcp_read(&self) -> Result<PTCPPacket, PTCPError> {
         println!("<<< {}", self.peer_addr().unwrap());
 
         let mut buf = [0u8; 4096];
         let n = self.recv(&mut buf).await.unwrap();
-        let packet = PTCPPacket::parse(&buf[0..n]);
+        let packet = PTCPPacket::parse(&buf[0..n])?;
         println!("{:?}", packet);
         println!("---");
 
-        packet
+        Ok(packet)
+    }
+
+    async fn ptcp_read_batch(&self, max: usize) -> std::io::Result<Vec<PTCPPacket>> {
+        crate::batch::ptcp_read_batch(self, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PTCPCommand;
+
+    #[test]
+    fn acks_do_not_consume_sequence_space() {
+        let mut session = PTCPSession::new();
+        let before = session.sent_offset();
+
+        let ack = session.send(PTCPBody::Command(PTCPCommand::Ack));
+        assert_eq!(ack.sent(), before);
+        assert_eq!(session.sent_offset(), before);
+    }
+
+    #[test]
+    fn payloads_advance_sent_by_their_wire_length() {
+        let mut session = PTCPSession::new();
+        let packet = session.send(PTCPBody::Payload(PTCPPayload {
+            realm: 0,
+            data: vec![0u8; 5],
+        }));
+
+        assert_eq!(packet.sent(), 0);
+        assert_eq!(session.sent_offset(), 17); // 5 bytes of data + 12 bytes of header
+    }
+
+    #[test]
+    fn recv_advances_by_the_peer_wire_length_and_ack_does_not() {
+        let mut sender = PTCPSession::new();
+        let mut receiver = PTCPSession::new();
+
+        let payload = sender.send(PTCPBody::Payload(PTCPPayload {
+            realm: 0,
+            data: vec![0u8; 5],
+        }));
+        receiver.recv(payload);
+        assert_eq!(receiver.recv_offset(), 17);
+
+        let ack = sender.send(PTCPBody::Command(PTCPCommand::Ack));
+        receiver.recv(ack);
+        assert_eq!(receiver.recv_offset(), 17);
+    }
+
+    #[test]
+    fn a_packets_recv_field_reports_the_senders_offset_at_send_time() {
+        let mut peer_a = PTCPSession::new();
+        let mut peer_b = PTCPSession::new();
+
+        let first = peer_a.send(PTCPBody::Payload(PTCPPayload {
+            realm: 0,
+            data: vec![1, 2, 3],
+        }));
+        peer_b.recv(first);
+
+        // peer_b's next send should report having received peer_a's bytes.
+        let reply = peer_b.send(PTCPBody::Payload(PTCPPayload {
+            realm: 0,
+            data: vec![4, 5],
+        }));
+        assert_eq!(reply.recv(), 15); // 3 bytes of data + 12 bytes of header
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let buf = vec![0u8; 24];
+        assert!(matches!(PTCPPacket::parse(&buf), Err(PTCPError::BadMagic)));
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let buf = vec![0u8; 10];
+        assert!(matches!(PTCPPacket::parse(&buf), Err(PTCPError::TruncatedHeader)));
     }
 }
\ No newline at end of file
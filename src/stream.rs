@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+use crate::command::PTCPCommand;
+use crate::ptcp::{PTCPBody, PTCPPacket, PTCPPayload, PTCPSession};
+
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+const DUPLEX_BUFFER: usize = 64 * 1024;
+const DATAGRAM_BUFFER: usize = 4096;
+const IDLE_TICK: Duration = Duration::from_secs(3600);
+
+/// How far past `session.recv_offset()` an out-of-order packet may land
+/// before it's dropped instead of buffered. Bounds the `reassembly` map so a
+/// peer claiming wildly-future offsets can't grow it without limit; a
+/// dropped packet is simply resent once the sender's retransmission timer
+/// fires and the gap has closed.
+const MAX_REASSEMBLY_WINDOW: u32 = 1024 * 1024;
+
+/// Hard cap on the number of out-of-order packets held at once, independent
+/// of the byte window above.
+const MAX_REASSEMBLY_ENTRIES: usize = 256;
+
+struct Unacked {
+    bytes: Vec<u8>,
+    len: u32,
+    rto: Duration,
+    deadline: Instant,
+}
+
+/// Wraps a [`PTCPSession`] and its `UdpSocket` into an ordered, reliable
+/// `AsyncRead`/`AsyncWrite` byte stream.
+///
+/// A background task owns the socket and session, maintaining an
+/// unacknowledged send buffer keyed by the `sent` byte offset (resent with
+/// exponential backoff until the peer's `recv` field advances past it) and a
+/// receive reassembly buffer keyed by the peer's `sent` offset (held until
+/// the gap fills), so realms look like ordered streams instead of raw
+/// datagram events.
+pub struct PTCPStream {
+    io: DuplexStream,
+}
+
+impl PTCPStream {
+    pub fn new(socket: UdpSocket, session: PTCPSession) -> PTCPStream {
+        let (local, remote) = tokio::io::duplex(DUPLEX_BUFFER);
+        tokio::spawn(run(socket, session, remote));
+        PTCPStream { io: local }
+    }
+}
+
+impl AsyncRead for PTCPStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PTCPStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+async fn run(socket: UdpSocket, mut session: PTCPSession, mut io: DuplexStream) {
+    let mut send_buf = [0u8; DATAGRAM_BUFFER];
+    let mut recv_buf = [0u8; DATAGRAM_BUFFER];
+    let mut unacked: BTreeMap<u32, Unacked> = BTreeMap::new();
+    let mut reassembly: BTreeMap<u32, PTCPPacket> = BTreeMap::new();
+
+    loop {
+        let deadline = unacked
+            .values()
+            .map(|entry| entry.deadline)
+            .min()
+            .unwrap_or_else(|| Instant::now() + IDLE_TICK);
+
+        tokio::select! {
+            n = io.read(&mut send_buf) => {
+                let n = match n {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+
+                let offset = session.sent_offset();
+                let len = n as u32 + 12;
+                let payload = PTCPPayload { realm: 0, data: send_buf[..n].to_vec() };
+                let packet = session.send(PTCPBody::Payload(payload));
+                let bytes = packet.serialize();
+
+                if socket.send(&bytes).await.is_err() {
+                    return;
+                }
+
+                unacked.insert(
+                    offset,
+                    Unacked { bytes, len, rto: INITIAL_RTO, deadline: Instant::now() + INITIAL_RTO },
+                );
+            }
+
+            received = socket.recv(&mut recv_buf) => {
+                let n = match received {
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+
+                let packet = match PTCPPacket::parse(&recv_buf[..n]) {
+                    Ok(packet) => packet,
+                    Err(_) => continue, // drop the malformed datagram, keep the stream alive
+                };
+
+                // Anything the peer's `recv` field now covers is acknowledged.
+                let acked_through = packet.recv();
+                unacked.retain(|&offset, entry| offset.wrapping_add(entry.len) > acked_through);
+
+                let offset = packet.sent();
+                if offset == session.recv_offset() {
+                    let mut delivered_payload = false;
+
+                    match deliver(&mut session, packet, &mut io).await {
+                        Some(was_payload) => delivered_payload |= was_payload,
+                        None => return,
+                    }
+                    while let Some(next) = reassembly.remove(&session.recv_offset()) {
+                        match deliver(&mut session, next, &mut io).await {
+                            Some(was_payload) => delivered_payload |= was_payload,
+                            None => return,
+                        }
+                    }
+
+                    // Only ack payload data; acking a command (including an
+                    // incoming ack) would make two peers volley acks at each
+                    // other forever, each answering the other's ack.
+                    if delivered_payload {
+                        let ack = session.send(PTCPBody::Command(PTCPCommand::Ack));
+                        let _ = socket.send(&ack.serialize()).await;
+                    }
+                } else if offset > session.recv_offset()
+                    && offset - session.recv_offset() <= MAX_REASSEMBLY_WINDOW
+                    && reassembly.len() < MAX_REASSEMBLY_ENTRIES
+                {
+                    reassembly.insert(offset, packet);
+                } else if offset < session.recv_offset() && matches!(packet.body, PTCPBody::Payload(_)) {
+                    // offset < expected and it carries data: we already
+                    // delivered this, so the peer must not have seen our
+                    // earlier ack (or it wouldn't still be retransmitting).
+                    // Re-ack instead of silently dropping it, or the peer
+                    // backs off to MAX_RTO and never learns it arrived.
+                    let ack = session.send(PTCPBody::Command(PTCPCommand::Ack));
+                    let _ = socket.send(&ack.serialize()).await;
+                }
+                // offset too far ahead, the reassembly buffer is already
+                // full, or it's a stale/duplicate command: drop it
+            }
+
+            _ = time::sleep_until(deadline) => {
+                let now = Instant::now();
+                for entry in unacked.values_mut().filter(|entry| entry.deadline <= now) {
+                    if socket.send(&entry.bytes).await.is_err() {
+                        return;
+                    }
+                    entry.rto = (entry.rto * 2).min(MAX_RTO);
+                    entry.deadline = now + entry.rto;
+                }
+            }
+        }
+    }
+}
+
+/// Applies a packet's bookkeeping to `session` and, if it carries a
+/// payload, writes the data to the application-facing half of the stream.
+/// Returns `None` once the application side has gone away, otherwise
+/// `Some(true)` if the packet carried a payload and `Some(false)` if it was
+/// a command/empty body that advanced counters but delivered nothing.
+async fn deliver(session: &mut PTCPSession, packet: PTCPPacket, io: &mut DuplexStream) -> Option<bool> {
+    let packet = session.recv(packet);
+    if let PTCPBody::Payload(payload) = packet.body {
+        return io.write_all(&payload.data).await.is_ok().then_some(true);
+    }
+    Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn reassembles_out_of_order_segments_in_order() {
+        let (socket, peer) = connected_pair().await;
+        let mut stream = PTCPStream::new(socket, PTCPSession::new());
+
+        let mut peer_session = PTCPSession::new();
+        let first = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"AAA".to_vec() }));
+        let second = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"BBB".to_vec() }));
+        let third = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"CCC".to_vec() }));
+
+        // Deliver out of order: the middle segment arrives first and should
+        // be held in the reassembly buffer until the gap in front of it closes.
+        peer.send(&second.serialize()).await.unwrap();
+        peer.send(&first.serialize()).await.unwrap();
+        peer.send(&third.serialize()).await.unwrap();
+
+        let mut received = [0u8; 9];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"AAABBBCCC");
+    }
+
+    #[tokio::test]
+    async fn redelivers_an_ack_for_a_stale_duplicate_segment() {
+        let (socket, peer) = connected_pair().await;
+        let mut stream = PTCPStream::new(socket, PTCPSession::new());
+
+        let mut peer_session = PTCPSession::new();
+        let first = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"AAA".to_vec() }));
+
+        peer.send(&first.serialize()).await.unwrap();
+
+        let mut received = [0u8; 3];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"AAA");
+
+        // Drain the ack the stream sent for the first delivery before
+        // resending the already-delivered segment, as if our own earlier ack
+        // to the peer had been lost.
+        let mut buf = [0u8; 4096];
+        peer.recv(&mut buf).await.unwrap();
+
+        peer.send(&first.serialize()).await.unwrap();
+
+        let n = peer.recv(&mut buf).await.unwrap();
+        let ack = PTCPPacket::parse(&buf[..n]).unwrap();
+        assert!(matches!(ack.body, PTCPBody::Command(ref command) if command.is_ack()));
+    }
+
+    #[tokio::test]
+    async fn new_data_still_flows_after_a_lost_ack_forces_a_retransmit() {
+        let (socket, peer) = connected_pair().await;
+        let mut stream = PTCPStream::new(socket, PTCPSession::new());
+
+        let mut peer_session = PTCPSession::new();
+        let first = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"AAA".to_vec() }));
+        let second = peer_session.send(PTCPBody::Payload(PTCPPayload { realm: 0, data: b"BBB".to_vec() }));
+
+        peer.send(&first.serialize()).await.unwrap();
+        let mut received = [0u8; 3];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"AAA");
+
+        // Our ack for `first` never makes it back to the peer, so it
+        // retransmits the same segment before sending anything new. If acks
+        // still occupied sequence space, or a stale duplicate were dropped
+        // without a fresh ack, this would desync `recv_offset` from what the
+        // peer expects and every later segment would sit in `reassembly`
+        // forever.
+        peer.send(&first.serialize()).await.unwrap();
+        let mut buf = [0u8; 4096];
+        let n = peer.recv(&mut buf).await.unwrap();
+        assert!(matches!(PTCPPacket::parse(&buf[..n]).unwrap().body, PTCPBody::Command(ref c) if c.is_ack()));
+
+        peer.send(&second.serialize()).await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"BBB");
+    }
+}